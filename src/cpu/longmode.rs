@@ -67,6 +67,182 @@ impl Registers {
         let new = low | (((val as u64) & 0xffu64) << 8);
         self.regs[i] = new;
     }
+
+    /// Read an 8-bit register by its ModR/M-style index (0..15), honoring the
+    /// REX-dependent byte-register encoding.
+    ///
+    /// Without a REX prefix, indices 4..7 select AH/CH/DH/BH (the high byte of
+    /// AX/CX/DX/BX). With any REX prefix present, those same indices instead select
+    /// SPL/BPL/SIL/DIL (the low byte of SP/BP/SI/DI), and AH/CH/DH/BH become
+    /// unreachable. Indices 0..3 and 8..15 (the latter only reachable via REX.B/R)
+    /// always address the low byte.
+    pub fn get_r8(&self, idx: usize, rex_present: bool) -> u8 {
+        if (4..=7).contains(&idx) && !rex_present {
+            self.get_r8h(idx - 4)
+        } else {
+            self.get_r8l(idx)
+        }
+    }
+
+    /// Write an 8-bit register by its ModR/M-style index; see `get_r8` for the
+    /// REX-dependent AH/CH/DH/BH vs SPL/BPL/SIL/DIL routing.
+    pub fn set_r8(&mut self, idx: usize, val: u8, rex_present: bool) {
+        if (4..=7).contains(&idx) && !rex_present {
+            self.set_r8h(idx - 4, val)
+        } else {
+            self.set_r8l(idx, val)
+        }
+    }
+
+    fn flag(&self, mask: u64) -> bool {
+        (self.rflags & mask) != 0
+    }
+
+    fn set_flag(&mut self, mask: u64, val: bool) {
+        if val {
+            self.rflags |= mask;
+        } else {
+            self.rflags &= !mask;
+        }
+    }
+
+    pub fn get_cf(&self) -> bool {
+        self.flag(flags::CF)
+    }
+
+    pub fn set_cf(&mut self, val: bool) {
+        self.set_flag(flags::CF, val)
+    }
+
+    pub fn get_pf(&self) -> bool {
+        self.flag(flags::PF)
+    }
+
+    pub fn set_pf(&mut self, val: bool) {
+        self.set_flag(flags::PF, val)
+    }
+
+    pub fn get_af(&self) -> bool {
+        self.flag(flags::AF)
+    }
+
+    pub fn set_af(&mut self, val: bool) {
+        self.set_flag(flags::AF, val)
+    }
+
+    pub fn get_zf(&self) -> bool {
+        self.flag(flags::ZF)
+    }
+
+    pub fn set_zf(&mut self, val: bool) {
+        self.set_flag(flags::ZF, val)
+    }
+
+    pub fn get_sf(&self) -> bool {
+        self.flag(flags::SF)
+    }
+
+    pub fn set_sf(&mut self, val: bool) {
+        self.set_flag(flags::SF, val)
+    }
+
+    pub fn get_df(&self) -> bool {
+        self.flag(flags::DF)
+    }
+
+    pub fn set_df(&mut self, val: bool) {
+        self.set_flag(flags::DF, val)
+    }
+
+    pub fn get_of(&self) -> bool {
+        self.flag(flags::OF)
+    }
+
+    pub fn set_of(&mut self, val: bool) {
+        self.set_flag(flags::OF, val)
+    }
+
+    pub fn get_if(&self) -> bool {
+        self.flag(flags::IF)
+    }
+
+    pub fn set_if(&mut self, val: bool) {
+        self.set_flag(flags::IF, val)
+    }
+
+    pub fn get_tf(&self) -> bool {
+        self.flag(flags::TF)
+    }
+
+    pub fn set_tf(&mut self, val: bool) {
+        self.set_flag(flags::TF, val)
+    }
+
+    /// I/O privilege level, a 2-bit field.
+    pub fn get_iopl(&self) -> u8 {
+        ((self.rflags & flags::IOPL) >> 12) as u8
+    }
+
+    pub fn set_iopl(&mut self, val: u8) {
+        self.rflags = (self.rflags & !flags::IOPL) | (((val as u64) & 0x3) << 12);
+    }
+
+    /// Evaluate a condition code predicate against the current flags.
+    pub fn evaluate(&self, cc: ConditionCode) -> bool {
+        match cc {
+            ConditionCode::O => self.get_of(),
+            ConditionCode::NO => !self.get_of(),
+            ConditionCode::B => self.get_cf(),
+            ConditionCode::AE => !self.get_cf(),
+            ConditionCode::E => self.get_zf(),
+            ConditionCode::NE => !self.get_zf(),
+            ConditionCode::BE => self.get_cf() || self.get_zf(),
+            ConditionCode::A => !self.get_cf() && !self.get_zf(),
+            ConditionCode::S => self.get_sf(),
+            ConditionCode::NS => !self.get_sf(),
+            ConditionCode::P => self.get_pf(),
+            ConditionCode::NP => !self.get_pf(),
+            ConditionCode::L => self.get_sf() != self.get_of(),
+            ConditionCode::GE => self.get_sf() == self.get_of(),
+            ConditionCode::LE => self.get_zf() || (self.get_sf() != self.get_of()),
+            ConditionCode::G => !self.get_zf() && (self.get_sf() == self.get_of()),
+        }
+    }
+}
+
+/// RFLAGS bit masks for the standard status and control flags.
+pub mod flags {
+    pub const CF: u64 = 1 << 0;
+    pub const PF: u64 = 1 << 2;
+    pub const AF: u64 = 1 << 4;
+    pub const ZF: u64 = 1 << 6;
+    pub const SF: u64 = 1 << 7;
+    pub const TF: u64 = 1 << 8;
+    pub const IF: u64 = 1 << 9;
+    pub const DF: u64 = 1 << 10;
+    pub const OF: u64 = 1 << 11;
+    pub const IOPL: u64 = 0x3 << 12;
+}
+
+/// The sixteen x86 condition codes used by Jcc/SETcc/CMOVcc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionCode {
+    O,
+    NO,
+    B,
+    AE,
+    E,
+    NE,
+    BE,
+    A,
+    S,
+    NS,
+    P,
+    NP,
+    L,
+    GE,
+    LE,
+    G,
 }
 
 impl fmt::Debug for Registers {
@@ -100,20 +276,727 @@ impl Rex {
             None
         }
     }
+
+    pub fn to_byte(self) -> u8 {
+        0x40 | (self.w as u8) << 3 | (self.r as u8) << 2 | (self.x as u8) << 1 | (self.b as u8)
+    }
+}
+
+/// Segment override selected by a legacy segment prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Cs,
+    Ss,
+    Ds,
+    Es,
+    Fs,
+    Gs,
+}
+
+/// REP/REPNE prefix state as seen by string/scalar instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepPrefix {
+    None,
+    Rep,
+    Repne,
 }
 
-/// Parse a prefix stream and return detected REX (if any) and consumed length.
-pub fn parse_prefixes(stream: &[u8]) -> (Option<Rex>, usize) {
+/// Decoded legacy + REX prefix stream, ready for a decoder to read the opcode at the
+/// returned offset.
+#[derive(Debug, Clone, Copy)]
+pub struct Prefixes {
+    /// Effective segment override, if any legacy segment prefix was seen.
+    pub segment: Option<Segment>,
+    /// 0x66 operand-size override.
+    pub operand_size_override: bool,
+    /// 0x67 address-size override.
+    pub address_size_override: bool,
+    /// 0xF0 LOCK prefix.
+    pub lock: bool,
+    /// 0xF3/0xF2 REP/REPNE prefix.
+    pub rep: RepPrefix,
+    /// The last REX byte seen, if it was the final prefix before the opcode.
+    pub rex: Option<Rex>,
+    /// A VEX or EVEX prefix, if one was seen. A VEX/EVEX prefix subsumes REX and any
+    /// 0x66/0xF2/0xF3 legacy prefix, so `rex` and `rep` are left at their defaults
+    /// when this is set.
+    pub vex: Option<VexContext>,
+}
+
+/// Parse a prefix stream and return the decoded `Prefixes` and consumed length.
+///
+/// REX must immediately precede the opcode: any legacy prefix following a REX byte
+/// invalidates it (it no longer applies), and only the REX byte that is itself
+/// immediately followed by the opcode takes effect.
+pub fn parse_prefixes(stream: &[u8]) -> (Prefixes, usize) {
     let mut pos = 0usize;
+    let mut segment: Option<Segment> = None;
+    let mut operand_size_override = false;
+    let mut address_size_override = false;
+    let mut lock = false;
+    let mut rep = RepPrefix::None;
     let mut rex: Option<Rex> = None;
 
-    if pos < stream.len() {
-        if let Some(r) = Rex::from_byte(stream[pos]) {
-            rex = Some(r);
+    while pos < stream.len() {
+        let byte = stream[pos];
+        // Any legacy prefix following a REX byte makes that REX not count.
+        match byte {
+            0x2e => {
+                segment = Some(Segment::Cs);
+                rex = None;
+            }
+            0x36 => {
+                segment = Some(Segment::Ss);
+                rex = None;
+            }
+            0x3e => {
+                segment = Some(Segment::Ds);
+                rex = None;
+            }
+            0x26 => {
+                segment = Some(Segment::Es);
+                rex = None;
+            }
+            0x64 => {
+                segment = Some(Segment::Fs);
+                rex = None;
+            }
+            0x65 => {
+                segment = Some(Segment::Gs);
+                rex = None;
+            }
+            0x66 => {
+                operand_size_override = true;
+                rex = None;
+            }
+            0x67 => {
+                address_size_override = true;
+                rex = None;
+            }
+            0xf0 => {
+                lock = true;
+                rex = None;
+            }
+            0xf3 => {
+                rep = RepPrefix::Rep;
+                rex = None;
+            }
+            0xf2 => {
+                rep = RepPrefix::Repne;
+                rex = None;
+            }
+            0xc5 | 0xc4 | 0x62 => {
+                if let Some((vex, len)) = parse_vex(&stream[pos..]) {
+                    pos += len;
+                    // A VEX/EVEX prefix subsumes REX and any 0x66/0xF2/0xF3 legacy
+                    // prefix, so those are reset to their defaults rather than
+                    // reflecting whatever (malformed) legacy prefixes preceded it.
+                    return (
+                        Prefixes {
+                            segment,
+                            operand_size_override: false,
+                            address_size_override,
+                            lock,
+                            rep: RepPrefix::None,
+                            rex: None,
+                            vex: Some(vex),
+                        },
+                        pos,
+                    );
+                } else {
+                    break;
+                }
+            }
+            _ => {
+                if let Some(r) = Rex::from_byte(byte) {
+                    rex = Some(r);
+                } else {
+                    break;
+                }
+            }
+        }
+        pos += 1;
+    }
+
+    (
+        Prefixes {
+            segment,
+            operand_size_override,
+            address_size_override,
+            lock,
+            rep,
+            rex,
+            vex: None,
+        },
+        pos,
+    )
+}
+
+/// Scale factor encoded in a SIB byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    One,
+    Two,
+    Four,
+    Eight,
+}
+
+impl Scale {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x3 {
+            0 => Scale::One,
+            1 => Scale::Two,
+            2 => Scale::Four,
+            _ => Scale::Eight,
+        }
+    }
+
+    pub fn multiplier(self) -> u32 {
+        match self {
+            Scale::One => 1,
+            Scale::Two => 2,
+            Scale::Four => 4,
+            Scale::Eight => 8,
+        }
+    }
+}
+
+/// A memory operand decoded from a ModR/M byte, optional SIB byte, and displacement.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryOperand {
+    /// Base register index (0..15); absent for the disp32-only and RIP-relative forms.
+    pub base: Option<usize>,
+    /// Index register and scale; absent when the SIB byte encodes "no index".
+    pub index: Option<(usize, Scale)>,
+    pub disp: i32,
+    /// mod==0, r/m==5: disp32 relative to the address of the next instruction.
+    pub rip_relative: bool,
+}
+
+/// The r/m operand of a ModR/M byte: either a register or a memory reference.
+#[derive(Debug, Clone, Copy)]
+pub enum RmOperand {
+    Register(usize),
+    Memory(MemoryOperand),
+}
+
+/// Decoded ModR/M byte, with any SIB byte and displacement folded in.
+#[derive(Debug, Clone, Copy)]
+pub struct ModRM {
+    /// Raw mod field, 0..=3.
+    pub md: u8,
+    /// reg field extended to 4 bits via REX.R; indexes into `Registers`' `regs` array.
+    pub reg: usize,
+    pub rm: RmOperand,
+}
+
+fn read_i32_le(stream: &[u8], pos: usize) -> Option<i32> {
+    stream
+        .get(pos..pos + 4)
+        .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decode a ModR/M byte (plus any SIB byte and displacement that follow it), honoring
+/// REX.R/X/B register extension. `addr_size_override` reflects the 0x67 prefix: under
+/// 32-bit addressing, mod==0/r/m==5 is a disp32-only operand rather than RIP-relative.
+///
+/// Returns the decoded `ModRM` and the number of bytes consumed (including the ModR/M
+/// byte itself), so callers can continue on to any trailing immediate. Returns `None`
+/// if `stream` is truncated before the ModR/M byte, the SIB byte, or the displacement
+/// it implies are fully present — this decodes untrusted guest instruction bytes, so a
+/// short read must not panic.
+pub fn decode_modrm(stream: &[u8], rex: Option<Rex>, addr_size_override: bool) -> Option<(ModRM, usize)> {
+    let mut pos = 0usize;
+    let byte = *stream.first()?;
+    pos += 1;
+
+    let md = (byte >> 6) & 0x3;
+    let reg_bits = (byte >> 3) & 0x7;
+    let rm_bits = byte & 0x7;
+
+    let rex_r = rex.is_some_and(|r| r.r);
+    let rex_x = rex.is_some_and(|r| r.x);
+    let rex_b = rex.is_some_and(|r| r.b);
+
+    let reg = reg_bits as usize | if rex_r { 0x8 } else { 0 };
+
+    if md == 3 {
+        let rm = rm_bits as usize | if rex_b { 0x8 } else { 0 };
+        return Some((
+            ModRM {
+                md,
+                reg,
+                rm: RmOperand::Register(rm),
+            },
+            pos,
+        ));
+    }
+
+    let mut base = None;
+    let mut index = None;
+    let mut rip_relative = false;
+
+    if rm_bits == 4 {
+        let sib = *stream.get(pos)?;
+        pos += 1;
+        let scale = Scale::from_bits(sib >> 6);
+        let index_bits = (sib >> 3) & 0x7;
+        let base_bits = sib & 0x7;
+
+        if index_bits != 4 || rex_x {
+            index = Some((index_bits as usize | if rex_x { 0x8 } else { 0 }, scale));
+        }
+
+        if base_bits == 5 && md == 0 {
+            base = None; // disp32 follows, no base register
+        } else {
+            base = Some(base_bits as usize | if rex_b { 0x8 } else { 0 });
+        }
+    } else if md == 0 && rm_bits == 5 {
+        if addr_size_override {
+            base = None; // disp32 absolute in 32-bit addressing
+        } else {
+            rip_relative = true;
+        }
+    } else {
+        base = Some(rm_bits as usize | if rex_b { 0x8 } else { 0 });
+    }
+
+    let disp = match (md, rm_bits) {
+        (0, 5) => {
+            let d = read_i32_le(stream, pos)?;
+            pos += 4;
+            d
+        }
+        (0, 4) if base.is_none() => {
+            let d = read_i32_le(stream, pos)?;
+            pos += 4;
+            d
+        }
+        (0, _) => 0,
+        (1, _) => {
+            let d = *stream.get(pos)? as i8 as i32;
             pos += 1;
+            d
+        }
+        (2, _) => {
+            let d = read_i32_le(stream, pos)?;
+            pos += 4;
+            d
+        }
+        _ => unreachable!("mod is masked to 2 bits"),
+    };
+
+    Some((
+        ModRM {
+            md,
+            reg,
+            rm: RmOperand::Memory(MemoryOperand {
+                base,
+                index,
+                disp,
+                rip_relative,
+            }),
+        },
+        pos,
+    ))
+}
+
+/// Implied leading opcode map encoded by a VEX/EVEX prefix's map field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodeMap {
+    /// 0F
+    Map0F,
+    /// 0F 38
+    Map0F38,
+    /// 0F 3A
+    Map0F3A,
+}
+
+/// Implied legacy prefix encoded by a VEX/EVEX prefix's pp field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpliedPrefix {
+    None,
+    Op66,
+    RepF3,
+    RepneF2,
+}
+
+fn decode_pp(pp: u8) -> ImpliedPrefix {
+    match pp & 0x3 {
+        0 => ImpliedPrefix::None,
+        1 => ImpliedPrefix::Op66,
+        2 => ImpliedPrefix::RepF3,
+        _ => ImpliedPrefix::RepneF2,
+    }
+}
+
+fn decode_map(mmmmm: u8) -> OpcodeMap {
+    match mmmmm & 0x1f {
+        2 => OpcodeMap::Map0F38,
+        3 => OpcodeMap::Map0F3A,
+        _ => OpcodeMap::Map0F,
+    }
+}
+
+/// Vector length selected by VEX.L or EVEX.L'L.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorLength {
+    Len128,
+    Len256,
+    Len512,
+}
+
+/// EVEX-only prefix fields with no VEX equivalent.
+#[derive(Debug, Clone, Copy)]
+pub struct EvexContext {
+    /// aaa: opmask register selector, K0..K7.
+    pub mask_reg: usize,
+    /// z: zeroing- rather than merging-masking.
+    pub zeroing: bool,
+    /// b: broadcast (memory operands) or static rounding/SAE (register operands).
+    pub broadcast_or_rounding: bool,
+}
+
+/// Decoded VEX (2- or 3-byte) or EVEX (4-byte) prefix.
+#[derive(Debug, Clone, Copy)]
+pub struct VexContext {
+    pub map: OpcodeMap,
+    pub pp: ImpliedPrefix,
+    /// vvvv: the second source register specifier, already un-inverted (0..15).
+    pub vvvv: usize,
+    pub length: VectorLength,
+    pub w: bool,
+    pub r: bool,
+    pub x: bool,
+    pub b: bool,
+    /// Present only for EVEX; `None` for VEX.
+    pub evex: Option<EvexContext>,
+}
+
+fn invert4(raw: u8) -> usize {
+    (!raw & 0xf) as usize
+}
+
+/// Decode a VEX or EVEX prefix at the start of `stream` (which must begin with
+/// 0xC5, 0xC4, or 0x62), returning the decoded context and bytes consumed.
+/// Returns `None` if the stream is too short for the form its leading byte implies.
+pub fn parse_vex(stream: &[u8]) -> Option<(VexContext, usize)> {
+    match *stream.first()? {
+        0xc5 => {
+            let b1 = *stream.get(1)?;
+            Some((
+                VexContext {
+                    map: OpcodeMap::Map0F,
+                    pp: decode_pp(b1),
+                    vvvv: invert4((b1 >> 3) & 0xf),
+                    length: if b1 & 0x04 != 0 {
+                        VectorLength::Len256
+                    } else {
+                        VectorLength::Len128
+                    },
+                    w: false,
+                    r: b1 & 0x80 == 0,
+                    // The 2-byte form has no room to encode X/B, so neither extension applies.
+                    x: false,
+                    b: false,
+                    evex: None,
+                },
+                2,
+            ))
+        }
+        0xc4 => {
+            let b1 = *stream.get(1)?;
+            let b2 = *stream.get(2)?;
+            Some((
+                VexContext {
+                    map: decode_map(b1 & 0x1f),
+                    pp: decode_pp(b2),
+                    vvvv: invert4((b2 >> 3) & 0xf),
+                    length: if b2 & 0x04 != 0 {
+                        VectorLength::Len256
+                    } else {
+                        VectorLength::Len128
+                    },
+                    w: b2 & 0x80 != 0,
+                    r: b1 & 0x80 == 0,
+                    x: b1 & 0x40 == 0,
+                    b: b1 & 0x20 == 0,
+                    evex: None,
+                },
+                3,
+            ))
+        }
+        0x62 => {
+            let p0 = *stream.get(1)?;
+            let p1 = *stream.get(2)?;
+            let p2 = *stream.get(3)?;
+            let length = match (p2 >> 5) & 0x3 {
+                0 => VectorLength::Len128,
+                1 => VectorLength::Len256,
+                _ => VectorLength::Len512,
+            };
+            Some((
+                VexContext {
+                    map: decode_map(p0 & 0x3),
+                    pp: decode_pp(p1),
+                    vvvv: invert4((p1 >> 3) & 0xf),
+                    length,
+                    w: p1 & 0x80 != 0,
+                    r: p0 & 0x80 == 0,
+                    x: p0 & 0x40 == 0,
+                    b: p0 & 0x20 == 0,
+                    evex: Some(EvexContext {
+                        mask_reg: (p2 & 0x7) as usize,
+                        zeroing: p2 & 0x80 != 0,
+                        broadcast_or_rounding: p2 & 0x10 != 0,
+                    }),
+                },
+                4,
+            ))
         }
+        _ => None,
+    }
+}
+
+/// Vector register file backing the XMM/YMM/ZMM banks (16 registers of up to 512
+/// bits) plus the eight AVX-512 opmask registers K0..K7, mirroring the layout of
+/// `Registers`' general-purpose `regs` array.
+#[derive(Clone)]
+pub struct VectorRegisters {
+    /// 16 lanes of 8 u64s (512 bits); XMM/YMM/ZMM are the low 128/256/512 bits.
+    zmm: [[u64; 8]; 16],
+    k: [u64; 8],
+}
+
+impl VectorRegisters {
+    pub fn new() -> Self {
+        VectorRegisters {
+            zmm: [[0u64; 8]; 16],
+            k: [0u64; 8],
+        }
+    }
+
+    pub fn get_xmm(&self, idx: usize) -> u128 {
+        let lanes = &self.zmm[idx & 0xf];
+        (lanes[0] as u128) | ((lanes[1] as u128) << 64)
+    }
+
+    pub fn set_xmm(&mut self, idx: usize, val: u128) {
+        let i = idx & 0xf;
+        self.zmm[i][0] = val as u64;
+        self.zmm[i][1] = (val >> 64) as u64;
+    }
+
+    pub fn get_ymm(&self, idx: usize) -> [u64; 4] {
+        let lanes = &self.zmm[idx & 0xf];
+        [lanes[0], lanes[1], lanes[2], lanes[3]]
+    }
+
+    pub fn set_ymm(&mut self, idx: usize, val: [u64; 4]) {
+        self.zmm[idx & 0xf][..4].copy_from_slice(&val);
+    }
+
+    pub fn get_zmm(&self, idx: usize) -> [u64; 8] {
+        self.zmm[idx & 0xf]
+    }
+
+    pub fn set_zmm(&mut self, idx: usize, val: [u64; 8]) {
+        self.zmm[idx & 0xf] = val;
+    }
+
+    pub fn get_k(&self, idx: usize) -> u64 {
+        self.k[idx & 0x7]
+    }
+
+    pub fn set_k(&mut self, idx: usize, val: u64) {
+        self.k[idx & 0x7] = val;
+    }
+}
+
+/// The width an encoded instruction operates on; drives whether REX.W is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandSize {
+    Size8,
+    Size16,
+    Size32,
+    Size64,
+}
+
+fn scale_bits(scale: Scale) -> u8 {
+    match scale {
+        Scale::One => 0,
+        Scale::Two => 1,
+        Scale::Four => 2,
+        Scale::Eight => 3,
+    }
+}
+
+/// Compute the REX byte needed for an operand, or `None` if no REX is required.
+///
+/// REX.W is set for a 64-bit operand size, REX.R/X/B when the reg/SIB-index/rm-base
+/// register index is >= 8, and REX is forced even with all bits clear when an 8-bit
+/// operand addresses register 4..7 (SPL/BPL/SIL/DIL rather than AH/CH/DH/BH) through the
+/// reg field or a register-direct r/m operand — a memory base register has no such ambiguity, so
+/// `rm_is_register` must be false when `rm_base` names a memory base instead.
+fn compute_rex(
+    size: OperandSize,
+    reg: usize,
+    rm_base: usize,
+    rm_is_register: bool,
+    sib_index: Option<usize>,
+) -> Option<Rex> {
+    let w = size == OperandSize::Size64;
+    let r = reg >= 8;
+    let b = rm_base >= 8;
+    let x = sib_index.is_some_and(|i| i >= 8);
+    let forces_byte_reg = size == OperandSize::Size8
+        && ((4..=7).contains(&reg) || (rm_is_register && (4..=7).contains(&rm_base)));
+
+    if w || r || x || b || forces_byte_reg {
+        Some(Rex { w, r, x, b })
+    } else {
+        None
+    }
+}
+
+fn encode_sib(index: Option<(usize, Scale)>, base: Option<usize>) -> u8 {
+    let (index_bits, scale_bits) = match index {
+        Some((idx, scale)) => ((idx & 0x7) as u8, scale_bits(scale)),
+        None => (0x04, 0), // 100 in the index field means "no index"
+    };
+    let base_bits = match base {
+        Some(b) => (b & 0x7) as u8,
+        None => 0x05,
+    };
+    (scale_bits << 6) | (index_bits << 3) | base_bits
+}
+
+/// Emit the ModR/M (+ SIB + displacement) bytes addressing `mem`, with `reg` in the
+/// reg field.
+fn encode_memory(buf: &mut Vec<u8>, reg: usize, mem: &MemoryOperand) {
+    let reg_bits = (reg as u8 & 0x7) << 3;
+
+    if mem.rip_relative {
+        buf.push(reg_bits | 0x05); // mod=00, rm=101
+        buf.extend_from_slice(&mem.disp.to_le_bytes());
+        return;
+    }
+
+    let needs_sib =
+        mem.index.is_some() || mem.base.is_none() || (mem.base.unwrap() & 0x7) == 4;
+
+    if !needs_sib {
+        let base_low = (mem.base.unwrap() & 0x7) as u8;
+        if mem.disp == 0 && base_low != 5 {
+            buf.push(reg_bits | base_low);
+        } else if let Ok(d8) = i8::try_from(mem.disp) {
+            buf.push(0x40 | reg_bits | base_low);
+            buf.push(d8 as u8);
+        } else {
+            buf.push(0x80 | reg_bits | base_low);
+            buf.extend_from_slice(&mem.disp.to_le_bytes());
+        }
+        return;
+    }
+
+    match mem.base {
+        None => {
+            buf.push(reg_bits | 0x04); // mod=00, rm=100 (SIB), no base
+            buf.push(encode_sib(mem.index, None));
+            buf.extend_from_slice(&mem.disp.to_le_bytes());
+        }
+        Some(base) => {
+            let base_low = base & 0x7;
+            if mem.disp == 0 && base_low != 5 {
+                buf.push(reg_bits | 0x04);
+                buf.push(encode_sib(mem.index, Some(base)));
+            } else if let Ok(d8) = i8::try_from(mem.disp) {
+                buf.push(0x40 | reg_bits | 0x04);
+                buf.push(encode_sib(mem.index, Some(base)));
+                buf.push(d8 as u8);
+            } else {
+                buf.push(0x80 | reg_bits | 0x04);
+                buf.push(encode_sib(mem.index, Some(base)));
+                buf.extend_from_slice(&mem.disp.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Emit `opcode` followed by a register-direct ModR/M byte encoding `reg, rm` (Intel's
+/// G-then-E operand order), prefixed with a REX byte if one is required.
+pub fn emit_reg_reg(buf: &mut Vec<u8>, opcode: u8, size: OperandSize, reg: usize, rm: usize) {
+    if let Some(rex) = compute_rex(size, reg, rm, true, None) {
+        buf.push(rex.to_byte());
+    }
+    buf.push(opcode);
+    buf.push(0xc0 | ((reg as u8 & 0x7) << 3) | (rm as u8 & 0x7));
+}
+
+/// Emit `opcode` followed by a memory ModR/M (+ SIB + displacement) encoding `reg, mem`
+/// (Intel's G-then-E operand order), prefixed with a REX byte if one is required.
+pub fn emit_reg_mem(buf: &mut Vec<u8>, opcode: u8, size: OperandSize, reg: usize, mem: &MemoryOperand) {
+    let sib_index = mem.index.map(|(idx, _)| idx);
+    let rm_base = mem.base.unwrap_or(0);
+    if let Some(rex) = compute_rex(size, reg, rm_base, false, sib_index) {
+        buf.push(rex.to_byte());
+    }
+    buf.push(opcode);
+    encode_memory(buf, reg, mem);
+}
+
+/// Which width an immediate was actually encoded in, so a caller can pick the matching
+/// opcode (e.g. 0x83 /r ib vs 0x81 /r id).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImmForm {
+    Imm8,
+    Imm32,
+    Imm64,
+}
+
+/// Whether `imm` fits in a sign-extended imm8.
+pub fn fits_sign_extended_i8(imm: i32) -> bool {
+    i8::try_from(imm).is_ok()
+}
+
+/// Whether `imm` fits in a sign-extended imm32.
+pub fn fits_sign_extended_i32(imm: i64) -> bool {
+    i32::try_from(imm).is_ok()
+}
+
+pub fn emit_imm8(buf: &mut Vec<u8>, imm: i8) {
+    buf.push(imm as u8);
+}
+
+pub fn emit_imm32(buf: &mut Vec<u8>, imm: i32) {
+    buf.extend_from_slice(&imm.to_le_bytes());
+}
+
+pub fn emit_imm64(buf: &mut Vec<u8>, imm: i64) {
+    buf.extend_from_slice(&imm.to_le_bytes());
+}
+
+/// Emit `imm` as a sign-extended imm8 when it fits, otherwise a full imm32.
+pub fn emit_imm32_compact(buf: &mut Vec<u8>, imm: i32) -> ImmForm {
+    if fits_sign_extended_i8(imm) {
+        emit_imm8(buf, imm as i8);
+        ImmForm::Imm8
+    } else {
+        emit_imm32(buf, imm);
+        ImmForm::Imm32
+    }
+}
+
+/// Emit `imm` as a sign-extended imm32 when it fits, otherwise a full imm64.
+pub fn emit_imm64_compact(buf: &mut Vec<u8>, imm: i64) -> ImmForm {
+    if fits_sign_extended_i32(imm) {
+        emit_imm32(buf, imm as i32);
+        ImmForm::Imm32
+    } else {
+        emit_imm64(buf, imm);
+        ImmForm::Imm64
     }
-    (rex, pos)
 }
 
 #[cfg(test)]
@@ -146,4 +1029,370 @@ mod tests {
         assert_eq!(cpu.registers.get_r64(0), 0x1122334455667788u64);
         assert_eq!(cpu.registers.get_r64(1), 0x1122334455667788u64);
     }
+
+    #[test]
+    fn parse_prefixes_plain_rex() {
+        let (p, len) = parse_prefixes(&[0x48]);
+        assert_eq!(len, 1);
+        assert!(p.rex.unwrap().w);
+        assert_eq!(p.segment, None);
+        assert_eq!(p.rep, RepPrefix::None);
+    }
+
+    #[test]
+    fn parse_prefixes_full_stream() {
+        // FS override, operand-size override, REPNE, REX.WB, then opcode byte.
+        let (p, len) = parse_prefixes(&[0x64, 0x66, 0xf2, 0x49, 0x01]);
+        assert_eq!(len, 4);
+        assert_eq!(p.segment, Some(Segment::Fs));
+        assert!(p.operand_size_override);
+        assert_eq!(p.rep, RepPrefix::Repne);
+        let rex = p.rex.unwrap();
+        assert!(rex.w);
+        assert!(rex.b);
+    }
+
+    #[test]
+    fn parse_prefixes_rex_not_last_is_ignored() {
+        // REX byte followed by a legacy prefix: the REX must not apply.
+        let (p, len) = parse_prefixes(&[0x48, 0x66, 0x01]);
+        assert_eq!(len, 2);
+        assert!(p.rex.is_none());
+        assert!(p.operand_size_override);
+    }
+
+    #[test]
+    fn decode_modrm_register_direct_with_rex() {
+        // mod=11, reg=000, rm=001 with REX.R and REX.B set -> reg=8 (r8), rm=9 (r9)
+        let rex = Rex {
+            w: true,
+            r: true,
+            x: false,
+            b: true,
+        };
+        let (m, len) = decode_modrm(&[0xc1], Some(rex), false).unwrap();
+        assert_eq!(len, 1);
+        assert_eq!(m.md, 3);
+        assert_eq!(m.reg, 8);
+        match m.rm {
+            RmOperand::Register(idx) => assert_eq!(idx, 9),
+            _ => panic!("expected register operand"),
+        }
+    }
+
+    #[test]
+    fn decode_modrm_rip_relative() {
+        // mod=00, reg=000, rm=101 -> RIP-relative, disp32 follows
+        let (m, len) = decode_modrm(&[0x05, 0x10, 0x00, 0x00, 0x00], None, false).unwrap();
+        assert_eq!(len, 5);
+        match m.rm {
+            RmOperand::Memory(mem) => {
+                assert!(mem.rip_relative);
+                assert_eq!(mem.base, None);
+                assert_eq!(mem.disp, 0x10);
+            }
+            _ => panic!("expected memory operand"),
+        }
+    }
+
+    #[test]
+    fn decode_modrm_sib_with_scaled_index_and_rex() {
+        // mod=01, reg=000, rm=100 (SIB) ; SIB: scale=10 (4), index=001, base=011
+        // REX.X extends index to 9, REX.B extends base to 11; disp8 = -1
+        let rex = Rex {
+            w: false,
+            r: false,
+            x: true,
+            b: true,
+        };
+        let (m, len) = decode_modrm(&[0x44, 0x8b, 0xff], Some(rex), false).unwrap();
+        assert_eq!(len, 3);
+        match m.rm {
+            RmOperand::Memory(mem) => {
+                assert_eq!(mem.base, Some(11));
+                let (idx, scale) = mem.index.unwrap();
+                assert_eq!(idx, 9);
+                assert_eq!(scale.multiplier(), 4);
+                assert_eq!(mem.disp, -1);
+            }
+            _ => panic!("expected memory operand"),
+        }
+    }
+
+    #[test]
+    fn r8_without_rex_addresses_high_byte() {
+        let mut regs = Registers::new();
+        regs.set_r64(0, 0x1122); // rax = 0x1122
+        // idx 4 without REX means AH, the high byte of AX (register 0).
+        assert_eq!(regs.get_r8(4, false), 0x11);
+        regs.set_r8(4, 0xaa, false);
+        assert_eq!(regs.get_r64(0), 0xaa22);
+    }
+
+    #[test]
+    fn r8_with_rex_addresses_low_byte_of_spl_bpl_sil_dil() {
+        let mut regs = Registers::new();
+        regs.set_r64(4, 0x1122); // rsp = 0x1122
+        // idx 4 with REX present means SPL, the low byte of RSP (register 4).
+        assert_eq!(regs.get_r8(4, true), 0x22);
+        regs.set_r8(4, 0xaa, true);
+        assert_eq!(regs.get_r64(4), 0x11aa);
+    }
+
+    #[test]
+    fn r8_high_index_always_addresses_low_byte() {
+        let mut regs = Registers::new();
+        regs.set_r64(9, 0x1122); // r9, only reachable via REX.B
+        assert_eq!(regs.get_r8(9, true), 0x22);
+    }
+
+    #[test]
+    fn flag_accessors_set_and_clear_bits() {
+        let mut regs = Registers::new();
+        assert!(!regs.get_zf());
+        regs.set_zf(true);
+        assert!(regs.get_zf());
+        assert_eq!(regs.rflags, flags::ZF);
+        regs.set_cf(true);
+        assert_eq!(regs.rflags, flags::ZF | flags::CF);
+        regs.set_zf(false);
+        assert_eq!(regs.rflags, flags::CF);
+    }
+
+    #[test]
+    fn iopl_is_a_two_bit_field() {
+        let mut regs = Registers::new();
+        regs.set_iopl(3);
+        assert_eq!(regs.get_iopl(), 3);
+        regs.set_cf(true);
+        assert_eq!(regs.get_iopl(), 3);
+    }
+
+    #[test]
+    fn evaluate_condition_codes() {
+        let mut regs = Registers::new();
+        regs.set_zf(true);
+        assert!(regs.evaluate(ConditionCode::E));
+        assert!(!regs.evaluate(ConditionCode::NE));
+        assert!(regs.evaluate(ConditionCode::BE));
+
+        let mut regs = Registers::new();
+        regs.set_sf(true);
+        assert!(regs.evaluate(ConditionCode::L));
+        assert!(!regs.evaluate(ConditionCode::GE));
+        assert!(regs.evaluate(ConditionCode::LE));
+
+        regs.set_of(true);
+        assert!(!regs.evaluate(ConditionCode::L));
+        assert!(regs.evaluate(ConditionCode::GE));
+        assert!(!regs.evaluate(ConditionCode::LE));
+    }
+
+    #[test]
+    fn parse_vex_two_byte_form() {
+        // C5 F9: R=1 (inverted 0 -> unset), vvvv=1111 (inverted -> 0), L=0, pp=01 (66)
+        let (vex, len) = parse_vex(&[0xc5, 0xf9]).unwrap();
+        assert_eq!(len, 2);
+        assert_eq!(vex.map, OpcodeMap::Map0F);
+        assert_eq!(vex.pp, ImpliedPrefix::Op66);
+        assert_eq!(vex.vvvv, 0);
+        assert_eq!(vex.length, VectorLength::Len128);
+        assert!(!vex.r);
+        assert!(!vex.x);
+        assert!(!vex.b);
+        assert!(vex.evex.is_none());
+    }
+
+    #[test]
+    fn parse_vex_three_byte_form_extends_rxb_and_w() {
+        // C4 02 85: R=1,X=1,B=1 (all inverted 0 -> set), map=0F38; W=1, vvvv=0000->15, L=1(256), pp=01(66)
+        let (vex, len) = parse_vex(&[0xc4, 0x02, 0x85]).unwrap();
+        assert_eq!(len, 3);
+        assert_eq!(vex.map, OpcodeMap::Map0F38);
+        assert!(vex.w);
+        assert!(vex.r);
+        assert!(vex.x);
+        assert!(vex.b);
+        assert_eq!(vex.vvvv, 15);
+        assert_eq!(vex.length, VectorLength::Len256);
+        assert_eq!(vex.pp, ImpliedPrefix::Op66);
+    }
+
+    #[test]
+    fn parse_vex_evex_form_decodes_mask_and_length() {
+        // 62 F1 FD 4B: pp=01(66) from P1 low bits, W=1; P2: z=0,L'L=10(512),b=0,aaa=011
+        let (vex, len) = parse_vex(&[0x62, 0xf1, 0xfd, 0x4b]).unwrap();
+        assert_eq!(len, 4);
+        assert!(vex.w);
+        assert_eq!(vex.length, VectorLength::Len512);
+        let evex = vex.evex.unwrap();
+        assert_eq!(evex.mask_reg, 3);
+        assert!(!evex.zeroing);
+    }
+
+    #[test]
+    fn parse_prefixes_recognizes_vex_and_clears_rex() {
+        // REX.W followed by a 2-byte VEX prefix: the REX must not survive.
+        let (p, len) = parse_prefixes(&[0x48, 0xc5, 0xf9]);
+        assert_eq!(len, 3);
+        assert!(p.rex.is_none());
+        assert!(p.vex.is_some());
+    }
+
+    #[test]
+    fn parse_prefixes_vex_also_resets_rep_and_operand_size_override() {
+        // A malformed-but-representable stream: operand-size override and REP followed
+        // by a 2-byte VEX prefix.
+        let (p, len) = parse_prefixes(&[0x66, 0xf3, 0xc5, 0xf9]);
+        assert_eq!(len, 4);
+        assert!(p.vex.is_some());
+        assert_eq!(p.rep, RepPrefix::None);
+        assert!(!p.operand_size_override);
+    }
+
+    #[test]
+    fn vector_registers_xmm_ymm_zmm_aliasing() {
+        let mut regs = VectorRegisters::new();
+        regs.set_zmm(0, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(regs.get_ymm(0), [1, 2, 3, 4]);
+        assert_eq!(regs.get_xmm(0), 1 | (2u128 << 64));
+
+        regs.set_xmm(1, 0xdead_beef);
+        assert_eq!(regs.get_ymm(1)[0], 0xdead_beef);
+
+        regs.set_k(2, 0b1010);
+        assert_eq!(regs.get_k(2), 0b1010);
+    }
+
+    #[test]
+    fn emit_reg_reg_suppresses_rex_when_not_needed() {
+        let mut buf = Vec::new();
+        emit_reg_reg(&mut buf, 0x01, OperandSize::Size32, 1, 2);
+        assert_eq!(buf, vec![0x01, 0xc0 | (1 << 3) | 2]);
+    }
+
+    #[test]
+    fn emit_reg_reg_sets_rex_w_r_b() {
+        let mut buf = Vec::new();
+        // reg=9 (REX.R), rm=10 (REX.B), 64-bit operand (REX.W)
+        emit_reg_reg(&mut buf, 0x01, OperandSize::Size64, 9, 10);
+        let rex = buf[0];
+        assert_eq!(rex & 0xf0, 0x40);
+        assert_ne!(rex & 0x08, 0); // W
+        assert_ne!(rex & 0x04, 0); // R
+        assert_ne!(rex & 0x01, 0); // B
+        assert_eq!(rex & 0x02, 0); // X unused
+        assert_eq!(buf[1], 0x01);
+        assert_eq!(buf[2], 0xc0 | (1 << 3) | 2); // reg/rm low 3 bits
+    }
+
+    #[test]
+    fn emit_reg_reg_byte_operand_forces_rex_for_spl() {
+        let mut buf = Vec::new();
+        // 8-bit operand on register 4 (SPL under REX) must force a REX byte even
+        // though no other bit is set, distinguishing it from AH.
+        emit_reg_reg(&mut buf, 0x00, OperandSize::Size8, 0, 4);
+        assert_eq!(buf[0] & 0xf0, 0x40);
+        assert_eq!(buf[0] & 0x0f, 0);
+    }
+
+    #[test]
+    fn emit_reg_mem_sib_and_rex_x() {
+        let mut buf = Vec::new();
+        let mem = MemoryOperand {
+            base: Some(0),
+            index: Some((9, Scale::Four)), // index >= 8 -> REX.X
+            disp: 0x10,
+            rip_relative: false,
+        };
+        emit_reg_mem(&mut buf, 0x03, OperandSize::Size32, 0, &mem);
+        assert_eq!(buf[0] & 0xf0, 0x40);
+        assert_ne!(buf[0] & 0x02, 0); // X
+        assert_eq!(buf[1], 0x03);
+        // mod=01 (disp8), reg=000, rm=100 (SIB)
+        assert_eq!(buf[2], 0x44);
+        assert_eq!(buf[4], 0x10);
+    }
+
+    #[test]
+    fn emit_reg_mem_byte_operand_does_not_force_rex_for_memory_base() {
+        // mov al, [rsp]: rsp (register 4) is only ambiguous with AH as a
+        // register-direct r/m, never as a memory base, so no REX should be emitted.
+        let mut buf = Vec::new();
+        let mem = MemoryOperand {
+            base: Some(4),
+            index: None,
+            disp: 0,
+            rip_relative: false,
+        };
+        emit_reg_mem(&mut buf, 0x8a, OperandSize::Size8, 0, &mem);
+        assert_eq!(buf[0], 0x8a); // opcode, no REX prefix byte before it
+    }
+
+    #[test]
+    fn emit_reg_mem_rip_relative() {
+        let mut buf = Vec::new();
+        let mem = MemoryOperand {
+            base: None,
+            index: None,
+            disp: -16,
+            rip_relative: true,
+        };
+        emit_reg_mem(&mut buf, 0x8b, OperandSize::Size64, 0, &mem);
+        // REX.W only
+        assert_eq!(buf[0], 0x48);
+        assert_eq!(buf[1], 0x8b);
+        assert_eq!(buf[2], 0x05); // mod=00, reg=000, rm=101
+        assert_eq!(i32::from_le_bytes(buf[3..7].try_into().unwrap()), -16);
+    }
+
+    #[test]
+    fn immediate_compact_forms() {
+        assert!(fits_sign_extended_i8(-1));
+        assert!(!fits_sign_extended_i8(200));
+        assert!(fits_sign_extended_i32(-1));
+        assert!(!fits_sign_extended_i32(i64::from(i32::MAX) + 1));
+
+        let mut buf = Vec::new();
+        assert_eq!(emit_imm32_compact(&mut buf, -1), ImmForm::Imm8);
+        assert_eq!(buf, vec![0xff]);
+
+        let mut buf = Vec::new();
+        assert_eq!(emit_imm32_compact(&mut buf, 1000), ImmForm::Imm32);
+        assert_eq!(buf, 1000i32.to_le_bytes().to_vec());
+
+        let mut buf = Vec::new();
+        assert_eq!(emit_imm64_compact(&mut buf, 1000), ImmForm::Imm32);
+        assert_eq!(buf, 1000i32.to_le_bytes().to_vec());
+
+        let mut buf = Vec::new();
+        let big = i64::from(i32::MAX) + 1;
+        assert_eq!(emit_imm64_compact(&mut buf, big), ImmForm::Imm64);
+        assert_eq!(buf, big.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn decode_modrm_sib_no_index() {
+        // mod=00, reg=000, rm=100 (SIB); SIB: scale=00, index=100 (none, no REX.X), base=000
+        let (m, len) = decode_modrm(&[0x04, 0x20], None, false).unwrap();
+        assert_eq!(len, 2);
+        match m.rm {
+            RmOperand::Memory(mem) => {
+                assert_eq!(mem.index, None);
+                assert_eq!(mem.base, Some(0));
+            }
+            _ => panic!("expected memory operand"),
+        }
+    }
+
+    #[test]
+    fn decode_modrm_truncated_streams_return_none_instead_of_panicking() {
+        assert!(decode_modrm(&[], None, false).is_none());
+        // mod=01, rm=100 (SIB) but the SIB byte is missing.
+        assert!(decode_modrm(&[0x44], None, false).is_none());
+        // mod=00, rm=101 (RIP-relative) but disp32 is truncated to one byte.
+        assert!(decode_modrm(&[0x05, 0x10], None, false).is_none());
+        // mod=01, rm=100 (SIB) with SIB present but the disp8 byte missing.
+        assert!(decode_modrm(&[0x44, 0x20], None, false).is_none());
+    }
 }